@@ -2,14 +2,20 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
 use super::error::SocketError;
-use super::protocol::{Feature, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp, VsockAddr};
+use super::protocol::{
+    Feature, VirtioVsockConfig, VirtioVsockEvent, VirtioVsockHdr, VirtioVsockOp, VsockAddr,
+    VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
+};
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
 use crate::transport::Transport;
 use crate::volatile::volread;
 use crate::Result;
 use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::mem::size_of;
+use core::num::Wrapping;
 use core::ptr::{null_mut, NonNull};
 use log::{debug, info};
 use zerocopy::{AsBytes, FromBytes};
@@ -23,20 +29,40 @@ pub(crate) const QUEUE_SIZE: usize = 8;
 /// The size in bytes of each buffer used in the RX virtqueue.
 const RX_BUFFER_SIZE: usize = 512;
 
+/// The size in bytes of each buffer used in the event virtqueue.
+const EVENT_BUFFER_SIZE: usize = size_of::<VirtioVsockEvent>();
+
+/// The maximum number of unsolicited `Rst` packets the driver will send in response to traffic
+/// for connections it has no state for, or to reject connection requests. This bounds the amount
+/// of control traffic a malicious or buggy peer can make us generate.
+const MAX_CONTROL_PACKETS: usize = 10000;
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct ConnectionInfo {
     pub dst: VsockAddr,
     pub src_port: u32,
     /// The last `buf_alloc` value the peer sent to us, indicating how much receive buffer space in
     /// bytes it has allocated for packet bodies.
-    peer_buf_alloc: u32,
+    ///
+    /// This is a free-running counter as defined by the virtio-vsock spec, so it is stored as a
+    /// `Wrapping<u32>` and wraps around at `u32::MAX` rather than overflowing.
+    peer_buf_alloc: Wrapping<u32>,
     /// The last `fwd_cnt` value the peer sent to us, indicating how many bytes of packet bodies it
     /// has finished processing.
-    peer_fwd_cnt: u32,
+    ///
+    /// This is a free-running counter, stored as `Wrapping<u32>` for the same reason as
+    /// `peer_buf_alloc`.
+    peer_fwd_cnt: Wrapping<u32>,
     /// The number of bytes of packet bodies which we have sent to the peer.
-    tx_cnt: u32,
+    ///
+    /// This is a free-running counter, stored as `Wrapping<u32>` for the same reason as
+    /// `peer_buf_alloc`.
+    tx_cnt: Wrapping<u32>,
     /// The number of bytes of packet bodies which we have received from the peer and handled.
-    fwd_cnt: u32,
+    ///
+    /// This is a free-running counter, stored as `Wrapping<u32>` for the same reason as
+    /// `peer_buf_alloc`.
+    fwd_cnt: Wrapping<u32>,
     /// Whether we have recently requested credit from the peer.
     ///
     /// This is set to true when we send a `VIRTIO_VSOCK_OP_CREDIT_REQUEST`, and false when we
@@ -56,8 +82,8 @@ impl ConnectionInfo {
     /// Updates this connection info with the peer buffer allocation and forwarded count from the
     /// given event.
     pub fn update_for_event(&mut self, event: &VsockEvent) {
-        self.peer_buf_alloc = event.buffer_status.buffer_allocation;
-        self.peer_fwd_cnt = event.buffer_status.forward_count;
+        self.peer_buf_alloc = Wrapping(event.buffer_status.buffer_allocation);
+        self.peer_fwd_cnt = Wrapping(event.buffer_status.forward_count);
 
         if let VsockEventType::CreditUpdate = event.event_type {
             self.has_pending_credit_request = false;
@@ -69,11 +95,13 @@ impl ConnectionInfo {
     /// This should be called once received data has been passed to the client, so there is buffer
     /// space available for more.
     pub fn done_forwarding(&mut self, length: usize) {
-        self.fwd_cnt += length as u32;
+        self.fwd_cnt += Wrapping(length as u32);
     }
 
+    /// Returns the number of bytes the peer's receive buffer can still accept, computed from the
+    /// free-running `buf_alloc`, `tx_cnt` and `fwd_cnt` counters.
     fn peer_free(&self) -> u32 {
-        self.peer_buf_alloc - (self.tx_cnt - self.peer_fwd_cnt)
+        (self.peer_buf_alloc - (self.tx_cnt - self.peer_fwd_cnt)).0
     }
 
     fn new_header(&self, src_cid: u64) -> VirtioVsockHdr {
@@ -82,7 +110,7 @@ impl ConnectionInfo {
             dst_cid: self.dst.cid.into(),
             src_port: self.src_port.into(),
             dst_port: self.dst.port.into(),
-            fwd_cnt: self.fwd_cnt.into(),
+            fwd_cnt: self.fwd_cnt.0.into(),
             ..Default::default()
         }
     }
@@ -136,6 +164,8 @@ pub enum VsockEventType {
         /// The reason for the disconnection.
         reason: DisconnectReason,
     },
+    /// The peer requests to open a connection with us.
+    ConnectionRequest,
     /// Data was received on the connection.
     Received {
         /// The length of the data in bytes.
@@ -145,6 +175,10 @@ pub enum VsockEventType {
     CreditRequest,
     /// The peer just sent us a credit update with nothing else.
     CreditUpdate,
+    /// The device told us that our CID may have changed (e.g. because of live migration), and
+    /// that all existing connections should be considered reset. `source` and `destination` are
+    /// meaningless for this event.
+    TransportReset,
 }
 
 /// Driver for a VirtIO socket device.
@@ -159,6 +193,9 @@ pub struct VirtIOSocket<H: Hal, T: Transport> {
     /// the device for its lifetime. The upper 32 bits of the CID are reserved and zeroed.
     guest_cid: u64,
     rx_queue_buffers: [NonNull<[u8; RX_BUFFER_SIZE]>; QUEUE_SIZE],
+    event_queue_buffers: [NonNull<[u8; EVENT_BUFFER_SIZE]>; QUEUE_SIZE],
+    /// The number of unsolicited `Rst` packets sent so far, bounded by `MAX_CONTROL_PACKETS`.
+    control_packets_sent: usize,
 }
 
 impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
@@ -174,6 +211,11 @@ impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
             // used anywhere else after the driver is destroyed.
             unsafe { drop(Box::from_raw(buffer.as_ptr())) };
         }
+        for buffer in self.event_queue_buffers {
+            // Safe because we obtained the event buffer pointer from Box::into_raw, and it won't
+            // be used anywhere else after the driver is destroyed.
+            unsafe { drop(Box::from_raw(buffer.as_ptr())) };
+        }
     }
 }
 
@@ -198,7 +240,7 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
 
         let mut rx = VirtQueue::new(&mut transport, RX_QUEUE_IDX)?;
         let tx = VirtQueue::new(&mut transport, TX_QUEUE_IDX)?;
-        let event = VirtQueue::new(&mut transport, EVENT_QUEUE_IDX)?;
+        let mut event = VirtQueue::new(&mut transport, EVENT_QUEUE_IDX)?;
 
         // Allocate and add buffers for the RX queue.
         let mut rx_queue_buffers = [null_mut(); QUEUE_SIZE];
@@ -212,10 +254,26 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         }
         let rx_queue_buffers = rx_queue_buffers.map(|ptr| NonNull::new(ptr).unwrap());
 
+        // Allocate and add buffers for the event queue, so that the device has somewhere to post
+        // transport-level events such as `VIRTIO_VSOCK_EVENT_TRANSPORT_RESET`.
+        let mut event_queue_buffers = [null_mut(); QUEUE_SIZE];
+        for i in 0..QUEUE_SIZE {
+            let mut buffer: Box<[u8; EVENT_BUFFER_SIZE]> = FromBytes::new_box_zeroed();
+            // Safe because the buffer lives as long as the queue, as specified in the function
+            // safety requirement, and we don't access it until it is popped.
+            let token = unsafe { event.add(&[], &mut [buffer.as_mut_slice()]) }?;
+            assert_eq!(i, token.into());
+            event_queue_buffers[i] = Box::into_raw(buffer);
+        }
+        let event_queue_buffers = event_queue_buffers.map(|ptr| NonNull::new(ptr).unwrap());
+
         transport.finish_init();
         if rx.should_notify() {
             transport.notify(RX_QUEUE_IDX);
         }
+        if event.should_notify() {
+            transport.notify(EVENT_QUEUE_IDX);
+        }
 
         Ok(Self {
             transport,
@@ -224,6 +282,8 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
             event,
             guest_cid,
             rx_queue_buffers,
+            event_queue_buffers,
+            control_packets_sent: 0,
         })
     }
 
@@ -253,6 +313,20 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         Ok(())
     }
 
+    /// Accepts a connection request from the peer described by the given connection info,
+    /// sending a response to complete the handshake.
+    ///
+    /// `connection_info` should have its peer buffer allocation and forwarded count populated
+    /// from the `VsockEventType::ConnectionRequest` event, so that credit accounting starts out
+    /// correct.
+    pub fn accept(&mut self, connection_info: &ConnectionInfo) -> Result {
+        let header = VirtioVsockHdr {
+            op: VirtioVsockOp::Response.into(),
+            ..connection_info.new_header(self.guest_cid)
+        };
+        self.send_packet_to_tx_queue(&header, &[])
+    }
+
     /// Requests the peer to send us a credit update for the given connection.
     fn request_credit(&mut self, connection_info: &ConnectionInfo) -> Result {
         let header = VirtioVsockHdr {
@@ -273,7 +347,7 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
             buf_alloc: 0.into(),
             ..connection_info.new_header(self.guest_cid)
         };
-        connection_info.tx_cnt += len;
+        connection_info.tx_cnt += Wrapping(len);
         self.send_packet_to_tx_queue(&header, buffer)
     }
 
@@ -310,6 +384,12 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
     /// A buffer must be provided to put the data in if there is some to
     /// receive.
     pub fn poll_recv(&mut self, buffer: &mut [u8]) -> Result<Option<VsockEvent>> {
+        // A transport-level event (e.g. a CID change) takes priority, as it invalidates every
+        // connection we know about.
+        if let Some(event) = self.poll_event_queue()? {
+            return Ok(Some(event));
+        }
+
         // Handle entries from the RX virtqueue until we find one that generates an event.
         let event = self.poll_rx_queue(buffer)?;
 
@@ -320,6 +400,64 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         Ok(event)
     }
 
+    /// Polls the event virtqueue for a transport-level event from the device.
+    ///
+    /// Returns `Ok(None)` if there is no pending event, or if the event isn't one we need to
+    /// surface to the caller.
+    fn poll_event_queue(&mut self) -> Result<Option<VsockEvent>> {
+        let Some(token) = self.event.peek_used() else {
+            return Ok(None);
+        };
+
+        // Safe because we maintain a consistent mapping of tokens to buffers, so we pass the same
+        // buffer to `pop_used` as we previously passed to `add` for the token. Once we add the
+        // buffer back to the event queue then we don't access it again until next time it is
+        // popped.
+        let event = unsafe {
+            let buffer = self.event_queue_buffers[usize::from(token)].as_mut();
+            let _len = self.event.pop_used(token, &[], &mut [buffer])?;
+
+            // Read the event from the buffer. Don't check the result yet, because we need to add
+            // the buffer back to the queue either way.
+            let event_result =
+                VirtioVsockEvent::read_from_prefix(buffer).ok_or(SocketError::BufferTooShort);
+
+            // Add the buffer back to the event queue.
+            let new_token = self.event.add(&[], &mut [buffer])?;
+            assert_eq!(new_token, token);
+
+            event_result
+        }?;
+        let id: u32 = event.id.into();
+
+        if self.event.should_notify() {
+            self.transport.notify(EVENT_QUEUE_IDX);
+        }
+
+        if id == VIRTIO_VSOCK_EVENT_TRANSPORT_RESET {
+            info!("Received VIRTIO_VSOCK_EVENT_TRANSPORT_RESET, guest CID may have changed");
+            let config = self.transport.config_space::<VirtioVsockConfig>()?;
+            // Safe because config is a valid pointer to the device configuration space.
+            self.guest_cid = unsafe {
+                volread!(config, guest_cid_low) as u64
+                    | (volread!(config, guest_cid_high) as u64) << 32
+            };
+            info!("guest cid: {:?}", self.guest_cid);
+
+            Ok(Some(VsockEvent {
+                source: VsockAddr::default(),
+                destination: VsockAddr::default(),
+                buffer_status: VsockBufferStatus {
+                    buffer_allocation: 0,
+                    forward_count: 0,
+                },
+                event_type: VsockEventType::TransportReset,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Requests to shut down the connection cleanly.
     ///
     /// This returns as soon as the request is sent; you should wait until `poll_recv` returns a
@@ -343,6 +481,20 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         Ok(())
     }
 
+    /// Sends a `Rst` for the given connection, e.g. because we have no state for it or because
+    /// the caller is rejecting a connection request.
+    ///
+    /// Returns `SocketError::TooManyControlPackets` once `MAX_CONTROL_PACKETS` such packets have
+    /// been sent, so that a malicious or misbehaving peer can't make us flood the TX queue with
+    /// unsolicited resets indefinitely.
+    pub fn reset_connection(&mut self, connection_info: &ConnectionInfo) -> Result {
+        if self.control_packets_sent >= MAX_CONTROL_PACKETS {
+            return Err(SocketError::TooManyControlPackets.into());
+        }
+        self.control_packets_sent += 1;
+        self.force_close(connection_info)
+    }
+
     fn send_packet_to_tx_queue(&mut self, header: &VirtioVsockHdr, buffer: &[u8]) -> Result {
         let _len = self.tx.add_notify_wait_pop(
             &[header.as_bytes(), buffer],
@@ -373,8 +525,12 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         match op {
             VirtioVsockOp::Request => {
                 header.check_data_is_empty()?;
-                // TODO: Send a Rst, or support listening.
-                Ok(None)
+                Ok(Some(VsockEvent {
+                    source,
+                    destination,
+                    buffer_status,
+                    event_type: VsockEventType::ConnectionRequest,
+                }))
             }
             VirtioVsockOp::Response => {
                 header.check_data_is_empty()?;
@@ -482,6 +638,280 @@ fn read_header_and_body(buffer: &[u8], body: &mut [u8]) -> Result<VirtioVsockHdr
     Ok(header)
 }
 
+/// The amount of per-connection receive buffer space the manager advertises to the peer via
+/// `buf_alloc`, so that a well-behaved peer doesn't send more than we're prepared to buffer.
+const PER_CONNECTION_BUFFER_CAPACITY: usize = RX_BUFFER_SIZE * QUEUE_SIZE;
+
+/// The number of bytes which must have been drained from a connection's receive buffer before the
+/// manager bothers telling the peer about the freed-up space, rather than sending a
+/// `CreditUpdate` after every single `recv` call.
+const CREDIT_UPDATE_THRESHOLD: usize = PER_CONNECTION_BUFFER_CAPACITY / 2;
+
+/// The state the manager keeps for a vsock connection, either established or requested with
+/// `connect` and awaiting the peer's response, on top of the bookkeeping that `ConnectionInfo`
+/// already does.
+struct Connection {
+    info: ConnectionInfo,
+    /// Bytes which have been received from the peer but not yet drained by the client via
+    /// `recv`.
+    buffer: VecDeque<u8>,
+    /// Bytes drained from `buffer` since we last told the peer about the freed-up space.
+    bytes_consumed_since_credit_update: usize,
+}
+
+impl Connection {
+    fn new(peer: VsockAddr, src_port: u32) -> Self {
+        Self {
+            info: ConnectionInfo::new(peer, src_port),
+            buffer: VecDeque::new(),
+            bytes_consumed_since_credit_update: 0,
+        }
+    }
+}
+
+/// A higher-level interface over `VirtIOSocket` which keeps track of the state of multiple vsock
+/// connections, so that callers don't need to juggle a `ConnectionInfo` per peer themselves.
+///
+/// Connections are identified by the combination of the peer's `VsockAddr` and the local
+/// `src_port` used to talk to them.
+pub struct VsockConnectionManager<H: Hal, T: Transport> {
+    driver: VirtIOSocket<H, T>,
+    connections: Vec<Connection>,
+    /// Local ports on which we are listening for incoming connections.
+    listening_ports: Vec<u32>,
+    /// Connections for which we have received a `VsockEventType::ConnectionRequest` on a
+    /// listening port, but which haven't yet been accepted or rejected.
+    pending_requests: Vec<ConnectionInfo>,
+}
+
+impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
+    /// Construct a new `VsockConnectionManager` wrapping the given low-level driver.
+    pub fn new(driver: VirtIOSocket<H, T>) -> Self {
+        Self {
+            driver,
+            connections: Vec::new(),
+            listening_ports: Vec::new(),
+            pending_requests: Vec::new(),
+        }
+    }
+
+    /// Allows incoming connections on the given local port to be accepted with `accept`.
+    pub fn listen(&mut self, port: u32) {
+        if !self.listening_ports.contains(&port) {
+            self.listening_ports.push(port);
+        }
+    }
+
+    /// Returns the CID which has been assigned to this guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.driver.guest_cid()
+    }
+
+    fn find_connection(&self, peer: VsockAddr, src_port: u32) -> Option<usize> {
+        self.connections.iter().position(|connection| {
+            connection.info.dst == peer && connection.info.src_port == src_port
+        })
+    }
+
+    fn get_connection(&self, peer: VsockAddr, src_port: u32) -> Result<usize> {
+        self.find_connection(peer, src_port)
+            .ok_or(SocketError::NotConnected.into())
+    }
+
+    fn get_pending_request(&self, peer: VsockAddr, src_port: u32) -> Result<usize> {
+        self.pending_requests
+            .iter()
+            .position(|info| info.dst == peer && info.src_port == src_port)
+            .ok_or(SocketError::NotConnected.into())
+    }
+
+    /// Accepts the pending connection request from the given peer on the given local port,
+    /// completing the handshake by sending a response to the peer.
+    pub fn accept(&mut self, peer: VsockAddr, src_port: u32) -> Result {
+        let index = self.get_pending_request(peer, src_port)?;
+        let connection_info = self.pending_requests.remove(index);
+        self.driver.accept(&connection_info)?;
+        // Tell the peer how much we can receive, so it doesn't wait forever for credit that was
+        // never advertised.
+        self.driver
+            .credit_update(&connection_info, PER_CONNECTION_BUFFER_CAPACITY as u32)?;
+        self.connections.push(Connection {
+            info: connection_info,
+            buffer: VecDeque::new(),
+            bytes_consumed_since_credit_update: 0,
+        });
+        Ok(())
+    }
+
+    /// Rejects the pending connection request from the given peer on the given local port, by
+    /// sending a reset to the peer.
+    pub fn reject(&mut self, peer: VsockAddr, src_port: u32) -> Result {
+        let index = self.get_pending_request(peer, src_port)?;
+        let connection_info = self.pending_requests.remove(index);
+        self.driver.reset_connection(&connection_info)
+    }
+
+    /// Sends a `Rst` to the peer for an event which doesn't belong to any connection we know
+    /// about, so that it doesn't keep waiting on a connection we have no state for.
+    fn reset_unrecognised_connection(&mut self, event: &VsockEvent) -> Result {
+        let connection_info = ConnectionInfo::new(event.source, event.destination.port);
+        self.driver.reset_connection(&connection_info)
+    }
+
+    /// Sends a request to connect to the given destination.
+    ///
+    /// This returns as soon as the request is sent; you should wait until `poll` returns a
+    /// `VsockEventType::Connected` event indicating that the peer has accepted the connection
+    /// before sending data.
+    ///
+    /// Returns `SocketError::ConnectionExists` if there is already a connection (pending or
+    /// established) to the given peer and local port.
+    pub fn connect(&mut self, peer: VsockAddr, src_port: u32) -> Result {
+        if self.find_connection(peer, src_port).is_some() {
+            return Err(SocketError::ConnectionExists.into());
+        }
+        self.driver.connect(peer, src_port)?;
+        self.connections.push(Connection::new(peer, src_port));
+        Ok(())
+    }
+
+    /// Sends the buffer to the connection identified by the given peer and local port.
+    pub fn send(&mut self, peer: VsockAddr, src_port: u32, buffer: &[u8]) -> Result {
+        let index = self.get_connection(peer, src_port)?;
+        self.driver.send(buffer, &mut self.connections[index].info)
+    }
+
+    /// Requests to shut down the connection identified by the given peer and local port cleanly.
+    pub fn shutdown(&mut self, peer: VsockAddr, src_port: u32) -> Result {
+        let index = self.get_connection(peer, src_port)?;
+        self.driver.shutdown(&self.connections[index].info)
+    }
+
+    /// Forcibly closes the connection identified by the given peer and local port, without
+    /// waiting for the peer.
+    pub fn force_close(&mut self, peer: VsockAddr, src_port: u32) -> Result {
+        let index = self.get_connection(peer, src_port)?;
+        self.driver.force_close(&self.connections[index].info)?;
+        self.connections.remove(index);
+        Ok(())
+    }
+
+    /// Polls the underlying driver for the next event, updating the state of the connection it
+    /// belongs to (if any) and returning it to the caller.
+    ///
+    /// A scratch buffer must be provided to copy packet bodies into; any data received for an
+    /// established connection is appended to that connection's internal receive buffer rather
+    /// than being given to the caller directly, so it is not lost even if the caller doesn't call
+    /// `recv` for that connection straight away. The buffer holds at most
+    /// `PER_CONNECTION_BUFFER_CAPACITY` bytes; received bytes beyond that are dropped. Call `recv`
+    /// to actually read buffered data.
+    pub fn poll(&mut self, buffer: &mut [u8]) -> Result<Option<VsockEvent>> {
+        let guest_cid = self.driver.guest_cid();
+        let Some(event) = self.driver.poll_recv(buffer)? else {
+            return Ok(None);
+        };
+
+        let index = self
+            .connections
+            .iter()
+            .position(|connection| event.matches_connection(&connection.info, guest_cid));
+
+        match &event.event_type {
+            VsockEventType::TransportReset => {
+                // Our CID may have changed, so every connection we know about is now dead.
+                self.connections.clear();
+                self.pending_requests.clear();
+            }
+            VsockEventType::ConnectionRequest => {
+                if self.listening_ports.contains(&event.destination.port) {
+                    let mut connection_info =
+                        ConnectionInfo::new(event.source, event.destination.port);
+                    connection_info.update_for_event(&event);
+                    self.pending_requests.push(connection_info);
+                } else {
+                    self.reset_unrecognised_connection(&event)?;
+                }
+            }
+            VsockEventType::Disconnected { .. } => {
+                if let Some(index) = index {
+                    self.connections.remove(index);
+                }
+            }
+            VsockEventType::Received { length } => {
+                if let Some(index) = index {
+                    let connection = &mut self.connections[index];
+                    connection.info.update_for_event(&event);
+                    // A well-behaved peer won't send more than the capacity we've advertised, but
+                    // don't trust it: drop anything beyond what we have room for rather than
+                    // growing the buffer without bound and later underflowing `buf_alloc`.
+                    let space =
+                        PER_CONNECTION_BUFFER_CAPACITY.saturating_sub(connection.buffer.len());
+                    let accepted = (*length).min(space);
+                    connection
+                        .buffer
+                        .extend(buffer[..accepted].iter().copied());
+                } else {
+                    self.reset_unrecognised_connection(&event)?;
+                }
+            }
+            // A freshly-connected peer, or one asking for our current credit, doesn't know how
+            // much buffer space we have until we tell it; without this it may never send data.
+            VsockEventType::Connected | VsockEventType::CreditRequest => {
+                if let Some(index) = index {
+                    self.connections[index].info.update_for_event(&event);
+                    self.driver.credit_update(
+                        &self.connections[index].info,
+                        PER_CONNECTION_BUFFER_CAPACITY as u32,
+                    )?;
+                } else {
+                    self.reset_unrecognised_connection(&event)?;
+                }
+            }
+            VsockEventType::CreditUpdate => {
+                if let Some(index) = index {
+                    self.connections[index].info.update_for_event(&event);
+                } else {
+                    self.reset_unrecognised_connection(&event)?;
+                }
+            }
+        }
+
+        Ok(Some(event))
+    }
+
+    /// Reads as much buffered data as will fit in `buffer` from the connection identified by the
+    /// given peer and local port, without waiting for more to arrive, and returns the number of
+    /// bytes read (which may be 0 if nothing is currently buffered).
+    ///
+    /// Once enough bytes have been drained to be worth telling the peer about, this sends it a
+    /// `CreditUpdate` reminding it of our (constant) buffer capacity, with `fwd_cnt` advanced to
+    /// reflect the bytes we've drained.
+    pub fn recv(&mut self, peer: VsockAddr, src_port: u32, buffer: &mut [u8]) -> Result<usize> {
+        let index = self.get_connection(peer, src_port)?;
+        let connection = &mut self.connections[index];
+
+        let read_length = buffer.len().min(connection.buffer.len());
+        for (dest, src) in buffer[..read_length]
+            .iter_mut()
+            .zip(connection.buffer.drain(..read_length))
+        {
+            *dest = src;
+        }
+        connection.info.done_forwarding(read_length);
+        connection.bytes_consumed_since_credit_update += read_length;
+
+        if connection.bytes_consumed_since_credit_update >= CREDIT_UPDATE_THRESHOLD {
+            connection.bytes_consumed_since_credit_update = 0;
+            self.driver.credit_update(
+                &self.connections[index].info,
+                PER_CONNECTION_BUFFER_CAPACITY as u32,
+            )?;
+        }
+
+        Ok(read_length)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -525,4 +955,275 @@ mod tests {
             VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap();
         assert_eq!(socket.guest_cid(), 0x00_0000_0042);
     }
-}
+
+    #[test]
+    fn peer_free_wraps_around() {
+        let mut connection_info = ConnectionInfo::new(
+            VsockAddr {
+                cid: 42,
+                port: 1234,
+            },
+            4321,
+        );
+        connection_info.peer_buf_alloc = Wrapping(1000);
+        connection_info.tx_cnt = Wrapping(u32::MAX - 100);
+        connection_info.peer_fwd_cnt = Wrapping(u32::MAX - 100);
+        assert_eq!(connection_info.peer_free(), 1000);
+
+        // Send enough bytes that `tx_cnt` wraps around past `u32::MAX`; `peer_free` should still
+        // reflect the 300 in-flight bytes rather than panicking or going nonsensical.
+        connection_info.tx_cnt += Wrapping(300);
+        assert_eq!(connection_info.peer_free(), 700);
+    }
+
+    const GUEST_CID: u64 = 66;
+    const HOST_CID: u64 = 2;
+    const HOST_PORT: u32 = 1234;
+    const GUEST_PORT: u32 = 4321;
+
+    /// Builds a `VsockConnectionManager` wrapping a `VirtIOSocket` backed by a `FakeTransport`,
+    /// and returns it along with the transport's shared state so that tests can inject packets
+    /// into the RX queue and inspect what was sent to the TX queue.
+    fn test_socket() -> (
+        VsockConnectionManager<FakeHal, FakeTransport<VirtioVsockConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let config_space = Box::leak(Box::new(VirtioVsockConfig {
+            guest_cid_low: ReadOnly::new(GUEST_CID as u32),
+            guest_cid_high: ReadOnly::new(0),
+        }));
+        let state = Arc::new(Mutex::new(State {
+            status: DeviceStatus::empty(),
+            driver_features: 0,
+            guest_page_size: 0,
+            interrupt_pending: false,
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        let socket = VsockConnectionManager::new(
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
+        );
+        (socket, state)
+    }
+
+    /// Simulates the device posting a packet to the RX queue, as if it had come from the peer.
+    fn inject_rx_packet(state: &Arc<Mutex<State>>, header: VirtioVsockHdr, body: &[u8]) {
+        let mut packet = header.as_bytes().to_vec();
+        packet.extend_from_slice(body);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(RX_QUEUE_IDX, &packet);
+    }
+
+    /// Returns the header of the next packet the driver sent to the TX queue.
+    fn sent_header(state: &Arc<Mutex<State>>) -> VirtioVsockHdr {
+        let bytes = state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+        VirtioVsockHdr::read_from_prefix(&bytes).unwrap()
+    }
+
+    #[test]
+    fn accept_sends_credit_update_so_peer_can_send_data() {
+        let (mut socket, state) = test_socket();
+        socket.listen(HOST_PORT);
+        let peer = VsockAddr {
+            cid: HOST_CID,
+            port: GUEST_PORT,
+        };
+
+        inject_rx_packet(
+            &state,
+            VirtioVsockHdr {
+                op: VirtioVsockOp::Request.into(),
+                src_cid: HOST_CID.into(),
+                dst_cid: GUEST_CID.into(),
+                src_port: GUEST_PORT.into(),
+                dst_port: HOST_PORT.into(),
+                ..Default::default()
+            },
+            &[],
+        );
+        let mut buffer = [0u8; 64];
+        let event = socket.poll(&mut buffer).unwrap().unwrap();
+        assert_eq!(event.event_type, VsockEventType::ConnectionRequest);
+
+        socket.accept(peer, HOST_PORT).unwrap();
+
+        // The handshake response...
+        let response = sent_header(&state);
+        assert_eq!(response.op().unwrap(), VirtioVsockOp::Response);
+
+        // ...followed immediately by a credit update advertising our full receive buffer, so a
+        // well-behaved peer knows it is safe to start sending data straight away.
+        let credit_update = sent_header(&state);
+        assert_eq!(credit_update.op().unwrap(), VirtioVsockOp::CreditUpdate);
+        assert_eq!(
+            u32::from(credit_update.buf_alloc),
+            PER_CONNECTION_BUFFER_CAPACITY as u32
+        );
+    }
+
+    #[test]
+    fn connected_event_sends_credit_update() {
+        let (mut socket, state) = test_socket();
+        let peer = VsockAddr {
+            cid: HOST_CID,
+            port: HOST_PORT,
+        };
+        socket.connect(peer, GUEST_PORT).unwrap();
+        // Drain the `Request` the driver just sent, so it isn't mistaken for what we assert below.
+        sent_header(&state);
+
+        inject_rx_packet(
+            &state,
+            VirtioVsockHdr {
+                op: VirtioVsockOp::Response.into(),
+                src_cid: HOST_CID.into(),
+                dst_cid: GUEST_CID.into(),
+                src_port: HOST_PORT.into(),
+                dst_port: GUEST_PORT.into(),
+                ..Default::default()
+            },
+            &[],
+        );
+        let mut buffer = [0u8; 64];
+        let event = socket.poll(&mut buffer).unwrap().unwrap();
+        assert_eq!(event.event_type, VsockEventType::Connected);
+
+        let credit_update = sent_header(&state);
+        assert_eq!(credit_update.op().unwrap(), VirtioVsockOp::CreditUpdate);
+        assert_eq!(
+            u32::from(credit_update.buf_alloc),
+            PER_CONNECTION_BUFFER_CAPACITY as u32
+        );
+    }
+
+    #[test]
+    fn connection_request_for_unlistened_port_is_reset() {
+        let (mut socket, state) = test_socket();
+        // Note: no call to `listen`.
+
+        inject_rx_packet(
+            &state,
+            VirtioVsockHdr {
+                op: VirtioVsockOp::Request.into(),
+                src_cid: HOST_CID.into(),
+                dst_cid: GUEST_CID.into(),
+                src_port: HOST_PORT.into(),
+                dst_port: GUEST_PORT.into(),
+                ..Default::default()
+            },
+            &[],
+        );
+        let mut buffer = [0u8; 64];
+        let event = socket.poll(&mut buffer).unwrap().unwrap();
+        assert_eq!(event.event_type, VsockEventType::ConnectionRequest);
+
+        let reset = sent_header(&state);
+        assert_eq!(reset.op().unwrap(), VirtioVsockOp::Rst);
+    }
+
+    #[test]
+    fn reset_connection_has_a_bounded_budget() {
+        let (mut socket, _state) = test_socket();
+        socket.driver.control_packets_sent = MAX_CONTROL_PACKETS;
+        let connection_info = ConnectionInfo::new(
+            VsockAddr {
+                cid: HOST_CID,
+                port: HOST_PORT,
+            },
+            GUEST_PORT,
+        );
+
+        let error = socket.driver.reset_connection(&connection_info).unwrap_err();
+        assert!(format!("{:?}", error).contains("TooManyControlPackets"));
+    }
+
+    #[test]
+    fn connect_twice_to_same_peer_and_port_is_rejected() {
+        let (mut socket, _state) = test_socket();
+        let peer = VsockAddr {
+            cid: HOST_CID,
+            port: HOST_PORT,
+        };
+        socket.connect(peer, GUEST_PORT).unwrap();
+
+        let error = socket.connect(peer, GUEST_PORT).unwrap_err();
+        assert!(format!("{:?}", error).contains("ConnectionExists"));
+    }
+
+    #[test]
+    fn poll_routes_events_to_the_right_connection_among_several() {
+        let (mut socket, state) = test_socket();
+        socket.listen(HOST_PORT);
+        let peer_a = VsockAddr {
+            cid: HOST_CID,
+            port: GUEST_PORT,
+        };
+        let peer_b = VsockAddr {
+            cid: HOST_CID,
+            port: GUEST_PORT + 1,
+        };
+
+        // Two distinct peers both connect inbound to the same listening port.
+        for peer in [peer_a, peer_b] {
+            inject_rx_packet(
+                &state,
+                VirtioVsockHdr {
+                    op: VirtioVsockOp::Request.into(),
+                    src_cid: HOST_CID.into(),
+                    dst_cid: GUEST_CID.into(),
+                    src_port: peer.port.into(),
+                    dst_port: HOST_PORT.into(),
+                    ..Default::default()
+                },
+                &[],
+            );
+            let mut buffer = [0u8; 64];
+            let event = socket.poll(&mut buffer).unwrap().unwrap();
+            assert_eq!(event.event_type, VsockEventType::ConnectionRequest);
+            socket.accept(peer, HOST_PORT).unwrap();
+            // Drain the handshake response and credit update sent for this connection.
+            sent_header(&state);
+            sent_header(&state);
+        }
+
+        // A data packet from `peer_b` should be routed to `peer_b`'s connection, not `peer_a`'s.
+        inject_rx_packet(
+            &state,
+            VirtioVsockHdr {
+                op: VirtioVsockOp::Rw.into(),
+                src_cid: HOST_CID.into(),
+                dst_cid: GUEST_CID.into(),
+                src_port: peer_b.port.into(),
+                dst_port: HOST_PORT.into(),
+                len: 5.into(),
+                ..Default::default()
+            },
+            b"hello",
+        );
+        let mut buffer = [0u8; 64];
+        let event = socket.poll(&mut buffer).unwrap().unwrap();
+        assert_eq!(event.source, peer_b);
+        assert_eq!(event.destination.port, HOST_PORT);
+        assert_eq!(event.event_type, VsockEventType::Received { length: 5 });
+
+        let mut received = [0u8; 5];
+        assert_eq!(socket.recv(peer_b, HOST_PORT, &mut received).unwrap(), 5);
+        assert_eq!(&received, b"hello");
+
+        // `peer_a`'s connection never received anything, so there is nothing to read from it.
+        let mut empty = [0u8; 5];
+        assert_eq!(socket.recv(peer_a, HOST_PORT, &mut empty).unwrap(), 0);
+    }
+}
\ No newline at end of file